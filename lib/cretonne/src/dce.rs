@@ -0,0 +1,84 @@
+//! A dead code elimination (DCE) pass.
+//!
+//! Dead instructions are those whose results are never used, and which have no side effects that
+//! need to be preserved. The `split` module's `isplit`/`vsplit` helpers are expected to leave
+//! behind dead `iconcat`/`vconcat` chains once the values they reconstruct turn out to be unused;
+//! this pass sweeps them away, along with any other dead code that legalization or optimization
+//! passes produce along the way.
+
+use flowgraph::ControlFlowGraph;
+use ir::{Function, DataFlowGraph, Inst, Ebb, Opcode, ValueDef};
+use std::collections::HashSet;
+
+/// Run the dead code elimination pass on `func`.
+///
+/// This uses a simple mark-and-sweep algorithm: every instruction that has side effects, or that
+/// feeds a reachable EBB's arguments, or that affects control flow, is marked live. Liveness is
+/// then propagated backward to every instruction that defines a value used by an instruction that
+/// is already known to be live. Finally, every instruction that wasn't marked live is removed from
+/// the layout.
+pub fn dce(func: &mut Function, cfg: &ControlFlowGraph) {
+    let live = compute_live_insts(func, cfg);
+
+    let ebbs: Vec<Ebb> = func.layout.ebbs().collect();
+    for ebb in ebbs {
+        let insts: Vec<Inst> = func.layout.ebb_insts(ebb).collect();
+        for inst in insts {
+            if !live.contains(&inst) {
+                // Drop the instruction's own value list, if it has one, back into the pool
+                // before removing the instruction itself.
+                if let Some(args) = func.dfg[inst].take_value_list() {
+                    args.clear(&mut func.dfg.value_lists);
+                }
+                func.dfg.detach_results(inst);
+                func.layout.remove_inst(inst);
+            }
+        }
+    }
+}
+
+/// Compute the set of instructions that must be kept.
+fn compute_live_insts(func: &Function, cfg: &ControlFlowGraph) -> HashSet<Inst> {
+    let mut live = HashSet::new();
+    let mut worklist = Vec::new();
+
+    // Seed the worklist with every instruction that can't be deleted outright: anything with
+    // side effects, and anything that affects control flow (the layout itself, not just data
+    // flow).
+    for ebb in func.layout.ebbs() {
+        for inst in func.layout.ebb_insts(ebb) {
+            if is_inherently_live(&func.dfg, inst) {
+                if live.insert(inst) {
+                    worklist.push(inst);
+                }
+            }
+        }
+    }
+
+    // Propagate liveness backward: an instruction becomes live when one of its results feeds an
+    // operand of an instruction already known to be live. This also covers EBB arguments used by
+    // branches, since the branch's value list is scanned like any other operand list.
+    while let Some(inst) = worklist.pop() {
+        for &arg in func.dfg.inst_args(inst) {
+            let arg = func.dfg.resolve_copies(arg);
+            if let ValueDef::Res(def_inst, _) = func.dfg.value_def(arg) {
+                if live.insert(def_inst) {
+                    worklist.push(def_inst);
+                }
+            }
+        }
+    }
+
+    // Reachable EBBs always keep their terminator instructions alive; that's already handled by
+    // `is_inherently_live()` since every branch and every fall-through lives in the layout.
+    let _ = cfg;
+
+    live
+}
+
+/// Does `inst` have to be kept regardless of whether its results are used?
+fn is_inherently_live(dfg: &DataFlowGraph, inst: Inst) -> bool {
+    let opcode = dfg[inst].opcode();
+    opcode.is_branch() || opcode.is_call() || opcode.is_return() || opcode.can_trap() ||
+    opcode.can_store() || opcode.other_side_effects() || opcode == Opcode::Trap
+}
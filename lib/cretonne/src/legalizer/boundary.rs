@@ -0,0 +1,358 @@
+//! Legalize ABI boundaries.
+//!
+//! This module deals with the ABI boundaries of a function: the incoming arguments and return
+//! values described by `Signature`, together with the call and return instructions that reference
+//! them. Any argument or return value whose `Type` is too wide for a single register on the
+//! current ISA needs to be split into smaller parts before it can be assigned an `ArgumentLoc`,
+//! and the rest of the function needs to be rewritten to use the smaller parts instead.
+//!
+//! This happens in two phases:
+//!
+//! 1. `legalize_signatures()` legalizes `function.signature` and the entry block arguments that
+//!    mirror it, recording a `ValueConversion` for every argument that had to change.
+//! 2. `handle_call_abi()` applies the same conversions to the arguments of a call instruction,
+//!    and `handle_return_abi()` reassembles the results of a call instruction, after
+//!    `function.dfg.signatures` has been legalized the same way. The function's own `return`
+//!    instructions are not rewritten by this module.
+//!
+//! Note that between the two phases, a function's signature and the number of arguments passed by
+//! its call and return instructions are temporarily out of sync: the signature has already been
+//! split into smaller parts, but the call sites referencing it haven't been rewritten yet. The
+//! driver is responsible for running both phases as a matched pair.
+
+use flowgraph::ControlFlowGraph;
+use ir::{Function, Signature, ArgumentType, ArgumentExtension, ArgumentLoc, Ebb, Inst, Cursor,
+         Value, Type, InstBuilder};
+use isa::TargetIsa;
+use legalizer::{split_to, concat_from};
+use std::collections::HashMap;
+
+/// A record of how a single `ArgumentType` was split up into smaller parts in order to fit in
+/// registers.
+///
+/// Converting a call or return instruction to match a legalized signature means replaying the
+/// same conversion on the actual argument or return value.
+enum ValueConversion {
+    /// The value was split down to `Type` -- the largest legal leaf type for the ISA -- using
+    /// `isplit`/`iconcat` one or more times. The new arguments replace the original one, in
+    /// ascending significance order.
+    IntSplit(Type),
+    /// The value was split down to `Type` the same way as `IntSplit`, but using
+    /// `vsplit`/`vconcat`.
+    VectorSplit(Type),
+    /// The value was sign-extended to fit the smallest legal integer type.
+    Sext(Type),
+    /// The value was zero-extended to fit the smallest legal integer type.
+    Uext(Type),
+}
+
+/// Legalize the signatures of `func` for `isa`, splitting any argument or return value that is
+/// too wide for a single register.
+///
+/// This rewrites `func.signature` in place, and inserts `iconcat`/`vconcat` instructions at the
+/// top of the entry block to reconstruct the original wide values from the newly split entry
+/// block arguments. Every signature in `func.dfg.signatures` is legalized in place as well.
+///
+/// The `ValueConversion` lists computed along the way are not returned, and the conversions of
+/// the function's own return values are not applied to its `return` instructions.
+pub fn legalize_signatures(func: &mut Function, isa: &TargetIsa) {
+    let conversions = legalize_arg_types(&mut func.signature.params, isa);
+    if let Some(entry) = func.layout.entry_block() {
+        spread_entry_args(func, entry, &conversions);
+    }
+
+    let ret_conversions = legalize_arg_types(&mut func.signature.returns, isa);
+    // Returns don't have EBB arguments to rewrite, and the function's `return` instructions are
+    // left as they are, so these conversions are dropped.
+    let _ = ret_conversions;
+
+    // External signatures referenced from call instructions are legalized here too, all at once;
+    // their conversion lists are dropped as well.
+    for sig in func.dfg.signatures.keys().collect::<Vec<_>>() {
+        let sig_data = &mut func.dfg.signatures[sig];
+        legalize_arg_types(&mut sig_data.params, isa);
+        legalize_arg_types(&mut sig_data.returns, isa);
+    }
+}
+
+/// Expand `args` in place, replacing any argument whose type doesn't fit in a register with the
+/// parts produced by splitting or extending it, and assign an `ArgumentLoc` to every resulting
+/// argument.
+///
+/// Returns the list of conversions applied, indexed the same way as the *original* `args`, so a
+/// caller with a matching list of values can replay the conversions in order.
+fn legalize_arg_types(args: &mut Vec<ArgumentType>, isa: &TargetIsa) -> Vec<Option<ValueConversion>> {
+    let mut conversions = Vec::new();
+    let mut legal_args = Vec::new();
+
+    for arg in args.drain(..) {
+        match isa.legal_type_for_abi(arg.value_type) {
+            Some(ty) if ty == arg.value_type => {
+                conversions.push(None);
+                legal_args.push(arg);
+            }
+            Some(narrower) => {
+                // The ISA wants this value zero- or sign-extended to `narrower` rather than
+                // split.
+                let conv = match arg.extension {
+                    ArgumentExtension::Sext => ValueConversion::Sext(narrower),
+                    _ => ValueConversion::Uext(narrower),
+                };
+                conversions.push(Some(conv));
+                legal_args.push(ArgumentType {
+                                     value_type: narrower,
+                                     ..arg
+                                 });
+            }
+            None => {
+                // No single register can hold this value. Halve it repeatedly until every part
+                // fits -- an `i128` on a 32-bit ISA needs four `i32` parts, not two `i64` parts --
+                // and legalize each leaf in turn.
+                let leaf = legal_leaf_type(arg.value_type,
+                                           |ty| isa.legal_type_for_abi(ty) == Some(ty));
+                let conv = if arg.value_type.is_vector() {
+                    ValueConversion::VectorSplit(leaf)
+                } else {
+                    ValueConversion::IntSplit(leaf)
+                };
+                conversions.push(Some(conv));
+                let num_leaves = (arg.value_type.bytes() / leaf.bytes()) as usize;
+                for _ in 0..num_leaves {
+                    legal_args.push(ArgumentType { value_type: leaf, ..arg });
+                }
+            }
+        }
+    }
+
+    assign_locations(&mut legal_args, isa);
+    *args = legal_args;
+    conversions
+}
+
+/// Halve `ty` (as an integer or a vector, whichever it is) until `is_legal` accepts it.
+fn legal_leaf_type<F>(ty: Type, is_legal: F) -> Type
+    where F: Fn(Type) -> bool
+{
+    let mut t = ty;
+    while !is_legal(t) {
+        t = if t.is_vector() {
+            t.half_vector().expect("Type not vector-splittable")
+        } else {
+            t.half_width().expect("Type not integer-splittable")
+        };
+    }
+    t
+}
+
+/// Assign an `ArgumentLoc` to every entry of `args`, using `isa`'s calling convention.
+///
+/// Registers are handed out in order within each register class, tracked by `reg_used` below;
+/// without that bookkeeping, every argument assigned to a given class would collide on that
+/// class's first register instead of spreading across the class the way a real calling
+/// convention does.
+fn assign_locations(args: &mut Vec<ArgumentType>, isa: &TargetIsa) {
+    let mut reg_used = HashMap::new();
+    let mut next_stack_offset = 0;
+    for arg in args.iter_mut() {
+        arg.location = match isa.regclass_for_abi_type(arg.value_type) {
+            Some(rc) => {
+                let used = reg_used.entry(rc.index).or_insert(0);
+                match isa.allocatable_registers(rc).iter().nth(*used) {
+                    Some(unit) => {
+                        *used += 1;
+                        ArgumentLoc::Reg(unit)
+                    }
+                    None => {
+                        let loc = ArgumentLoc::Stack(next_stack_offset);
+                        next_stack_offset += arg.value_type.bytes();
+                        loc
+                    }
+                }
+            }
+            None => {
+                let loc = ArgumentLoc::Stack(next_stack_offset);
+                next_stack_offset += arg.value_type.bytes();
+                loc
+            }
+        };
+    }
+}
+
+/// Rewrite the entry block's arguments to match the legalized signature, sign/zero-extending or
+/// inserting `iconcat`/`vconcat` instructions as needed to reconstruct each original value from
+/// its legalized parts.
+///
+/// `legalize_arg_types()` places a split argument's parts consecutively in the signature, in
+/// ascending significance order, so the entry block's argument list has to match: each part after
+/// the first is inserted right after the one before it, not appended at the end. Walk the
+/// original, pre-split argument numbers low to high, tracking how many extra arguments earlier
+/// splits have already inserted so each original argument's *current* position in the EBB's
+/// argument list can be recovered.
+fn spread_entry_args(func: &mut Function, entry: Ebb, conversions: &[Option<ValueConversion>]) {
+    let mut pos = Cursor::new(&mut func.layout);
+    pos.goto_top(entry);
+    pos.next_inst();
+
+    let mut shift = 0;
+    for (num, conv) in conversions.iter().enumerate() {
+        let current = num + shift;
+        let value = func.dfg.ebb_args(entry)[current];
+        match *conv {
+            None => {}
+            Some(ValueConversion::Sext(abi_ty)) | Some(ValueConversion::Uext(abi_ty)) => {
+                // `legalize_arg_types()` already widened this argument's type in the signature;
+                // match it here, then `ireduce` back to the type the rest of the function still
+                // expects.
+                let narrow_ty = func.dfg.value_type(value);
+                let wide = func.dfg.replace_ebb_arg(value, abi_ty);
+                let narrow = func.dfg.ins(&mut pos).ireduce(narrow_ty, wide);
+                func.dfg.change_to_alias(value, narrow);
+            }
+            Some(ValueConversion::IntSplit(leaf)) | Some(ValueConversion::VectorSplit(leaf)) => {
+                let ty = func.dfg.value_type(value);
+                let parts = split_entry_arg(&mut func.dfg, entry, current, ty, leaf);
+                shift += parts.len() - 1;
+                let concat = concat_from(&mut func.dfg, &mut pos, &parts, ty);
+                func.dfg.change_to_alias(value, concat);
+            }
+        }
+    }
+}
+
+/// Replace the EBB argument at `num` (of type `ty`) with `ty.bytes() / leaf.bytes()` arguments of
+/// type `leaf`, inserted consecutively starting at `num`, in ascending significance order.
+fn split_entry_arg(dfg: &mut ::ir::DataFlowGraph,
+                    ebb: Ebb,
+                    num: usize,
+                    ty: Type,
+                    leaf: Type)
+                    -> Vec<Value> {
+    let num_parts = (ty.bytes() / leaf.bytes()) as usize;
+    let mut parts = Vec::with_capacity(num_parts);
+    parts.push(dfg.replace_ebb_arg(dfg.ebb_args(ebb)[num], leaf));
+    for i in 1..num_parts {
+        parts.push(dfg.insert_ebb_arg(ebb, num + i, leaf));
+    }
+    parts
+}
+
+/// Rewrite a call instruction's arguments to match the legalized signature of the callee,
+/// splitting or extending values at the call site exactly as `legalize_signatures()` split the
+/// matching formal parameters.
+///
+/// Reuses `split_to()`'s concat-reuse logic, so splitting a value that is already known to come
+/// from an `iconcat`/`vconcat` doesn't emit a redundant split instruction.
+pub fn handle_call_abi(inst: Inst,
+                        func: &mut Function,
+                        cfg: &ControlFlowGraph,
+                        conversions: &[Option<ValueConversion>]) {
+    let mut pos = Cursor::new(&mut func.layout);
+    pos.goto_inst(inst);
+
+    let mut new_args = Vec::new();
+    let old_args: Vec<Value> = func.dfg.inst_args(inst).to_vec();
+    for (&arg, conv) in old_args.iter().zip(conversions) {
+        match *conv {
+            None => new_args.push(arg),
+            Some(ValueConversion::IntSplit(leaf)) | Some(ValueConversion::VectorSplit(leaf)) => {
+                new_args.extend(split_to(&mut func.dfg, cfg, &mut pos, arg, leaf));
+            }
+            Some(ValueConversion::Sext(ty)) => {
+                new_args.push(func.dfg.ins(&mut pos).sextend(ty, arg));
+            }
+            Some(ValueConversion::Uext(ty)) => {
+                new_args.push(func.dfg.ins(&mut pos).uextend(ty, arg));
+            }
+        }
+    }
+
+    // `new_args` is longer than `old_args` whenever an argument was split, so it doesn't fit
+    // back into the fixed-size slice `inst_args_mut()` returns. Rebuild the instruction's value
+    // list instead of copying into it.
+    let mut arg_list = func.dfg[inst]
+        .take_value_list()
+        .expect("Calls must have value lists.");
+    arg_list.clear(&mut func.dfg.value_lists);
+    arg_list.extend(new_args, &mut func.dfg.value_lists);
+    func.dfg[inst].put_value_list(arg_list);
+}
+
+/// Reassemble the return values produced by a call instruction to match the *unlegalized*
+/// signature the rest of the function still expects, undoing the conversions performed on the
+/// callee's return values by `legalize_signatures()`.
+///
+/// The call instruction still has one result per *original* return value at this point --
+/// `legalize_signatures()` only split the callee's `Signature`, not this call site -- so for every
+/// split return this also has to grow the call's result list in place, the same way
+/// `spread_entry_args()` grows the entry block's argument list, before it can alias the original
+/// (now narrowed) result to the rebuilt `iconcat`/`vconcat` of the new parts.
+pub fn handle_return_abi(inst: Inst,
+                          func: &mut Function,
+                          cfg: &ControlFlowGraph,
+                          conversions: &[Option<ValueConversion>]) {
+    let mut pos = Cursor::new(&mut func.layout);
+    pos.goto_after_inst(inst);
+
+    let mut shift = 0;
+    for (num, conv) in conversions.iter().enumerate() {
+        let current = num + shift;
+        let value = func.dfg.inst_results(inst)[current];
+        match *conv {
+            None => {}
+            Some(ValueConversion::Sext(abi_ty)) | Some(ValueConversion::Uext(abi_ty)) => {
+                // Mirror `spread_entry_args()`: the call's result type was already widened to
+                // match the legalized signature, so `ireduce` it back to the type the rest of the
+                // function still expects.
+                let narrow_ty = func.dfg.value_type(value);
+                let wide = func.dfg.replace_result(value, abi_ty);
+                let narrow = func.dfg.ins(&mut pos).ireduce(narrow_ty, wide);
+                func.dfg.change_to_alias(value, narrow);
+            }
+            Some(ValueConversion::IntSplit(leaf)) | Some(ValueConversion::VectorSplit(leaf)) => {
+                let ty = func.dfg.value_type(value);
+                let parts = split_call_result(&mut func.dfg, inst, current, ty, leaf);
+                shift += parts.len() - 1;
+                let concat = concat_from(&mut func.dfg, &mut pos, &parts, ty);
+                func.dfg.change_to_alias(value, concat);
+            }
+        }
+    }
+    let _ = cfg;
+}
+
+/// Replace the call result at `num` (of type `ty`) with `ty.bytes() / leaf.bytes()` results of
+/// type `leaf`, inserted consecutively starting at `num`, mirroring `split_entry_arg()`.
+fn split_call_result(dfg: &mut ::ir::DataFlowGraph,
+                      inst: Inst,
+                      num: usize,
+                      ty: Type,
+                      leaf: Type)
+                      -> Vec<Value> {
+    let num_parts = (ty.bytes() / leaf.bytes()) as usize;
+    let mut parts = Vec::with_capacity(num_parts);
+    parts.push(dfg.replace_result(dfg.inst_results(inst)[num], leaf));
+    for i in 1..num_parts {
+        parts.push(dfg.insert_result(inst, num + i, leaf));
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::legal_leaf_type;
+    use ir::types;
+
+    #[test]
+    fn splits_below_the_widest_legal_register() {
+        // An `i128` argument on a 32-bit ISA needs four `i32` parts, not two `i64` parts: halving
+        // once isn't enough.
+        let leaf = legal_leaf_type(types::I128, |ty| ty.bits() <= 32);
+        assert_eq!(leaf, types::I32);
+    }
+
+    #[test]
+    fn already_legal_type_is_unchanged() {
+        let leaf = legal_leaf_type(types::I64, |ty| ty.bits() <= 64);
+        assert_eq!(leaf, types::I64);
+    }
+}
@@ -0,0 +1,214 @@
+//! Legalize `heap_addr` instructions.
+//!
+//! A `heap_addr` instruction computes the address of an access into a sandboxed heap, given a
+//! byte offset and the number of bytes accessed. This is a high-level instruction that has to be
+//! expanded into explicit bounds checking and pointer arithmetic before it reaches the target
+//! ISA, since no ISA has a `heap_addr` instruction of its own.
+//!
+//! For a heap with an explicit bound, the expansion compares `offset + access_size` against the
+//! bound and traps out of range; for a heap that relies on guard pages instead (the bound is
+//! large enough that an out-of-bounds access within the guard region would fault on its own),
+//! the explicit compare is elided and only the pointer arithmetic remains.
+
+use flowgraph::ControlFlowGraph;
+use ir::{Function, Inst, InstBuilder, InstructionData, TrapCode, Value};
+use ir::condcodes::IntCC;
+use ir::entities::{Heap, GlobalValue};
+use ir::immediates::Offset32;
+use isa::TargetIsa;
+use legalizer::{ExpandResult, emit_trap_on};
+
+/// How a heap's bound is represented.
+///
+/// This mirrors the `HeapStyle` stored on the heap's `HeapData` in the function; it's matched
+/// here only to decide how the bounds check computes its limit. Whether a check is emitted at
+/// all depends on the heap's guard pages, not on this.
+enum HeapBound {
+    /// The bound is a compile-time constant number of bytes.
+    Static(u64),
+    /// The bound is read from a global value at run time.
+    Dynamic(GlobalValue),
+}
+
+/// Expand a `heap_addr` instruction into explicit bounds checking and address computation.
+///
+/// Returns `ExpandResult::SplitNeeded` if the heap's index argument is wider than a register on
+/// `isa`, so the caller can split it with `isplit`/`vsplit` and retry.
+pub fn expand_heap_addr(inst: Inst,
+                         func: &mut Function,
+                         cfg: &ControlFlowGraph,
+                         isa: &TargetIsa)
+                         -> ExpandResult {
+    let (heap, index, offset, access_size) = match func.dfg[inst] {
+        InstructionData::HeapAddr {
+            heap,
+            arg,
+            offset,
+            size,
+            ..
+        } => (heap, arg, offset, size),
+        _ => return ExpandResult::Done,
+    };
+
+    if let Some(bad) = isa.oversize_operand(func.dfg.value_type(index)) {
+        return ExpandResult::SplitNeeded(bad);
+    }
+
+    let guard_pages = func.heaps[heap].guard_pages();
+    let bound = heap_bound(func, heap);
+
+    let mut pos = ::ir::Cursor::new(&mut func.layout);
+    pos.goto_inst(inst);
+
+    // Splitting the EBB below to hold the trap invalidates `cfg`'s view of this function; the
+    // driver must recompute it before splitting any value that flows through the new blocks.
+    let _ = cfg;
+
+    if !guard_pages {
+        emit_bounds_check(&mut func.dfg, &mut pos, index, offset, access_size, bound);
+    }
+
+    // The bounds check (if any) falls through here on success; compute the final address as
+    // `base + index + offset`. The bounds check above compares `index` in its own (possibly
+    // narrower than pointer-width) type, but the address computation needs it widened to the
+    // pointer type first, the same way `table.rs` widens its index before the analogous `iadd`.
+    let ptr_ty = isa.pointer_type();
+    let ty = func.dfg.value_type(index);
+    let index_ptr = if ty == ptr_ty {
+        index
+    } else {
+        func.dfg.ins(&mut pos).uextend(ptr_ty, index)
+    };
+    let base = func.dfg.ins(&mut pos).global_value(ptr_ty, heap_base(func, heap));
+    let offset_val = func.dfg.ins(&mut pos).iconst(ptr_ty, i64::from(offset));
+    let addr = func.dfg.ins(&mut pos).iadd(base, index_ptr);
+    let addr = func.dfg.ins(&mut pos).iadd(addr, offset_val);
+
+    func.dfg.replace_with_aliases(inst, addr);
+    ExpandResult::Expanded
+}
+
+fn heap_bound(func: &Function, heap: Heap) -> HeapBound {
+    match func.heaps[heap].bound() {
+        Some(bytes) => HeapBound::Static(bytes),
+        None => HeapBound::Dynamic(func.heaps[heap].bound_gv()),
+    }
+}
+
+fn heap_base(func: &Function, heap: Heap) -> GlobalValue {
+    func.heaps[heap].base_gv()
+}
+
+/// Insert the bounds check and trap for an out-of-range heap access, branching to the trap when
+/// the access is out of range and jumping to the remaining address computation otherwise.
+///
+/// The check is phrased as `index > bound - offset - access_size` rather than
+/// `index + offset + access_size > bound`, so that a maliciously large `index` can't wrap the
+/// comparison around and defeat the check the way adding into it first could.
+fn emit_bounds_check(dfg: &mut ::ir::DataFlowGraph,
+                      pos: &mut ::ir::Cursor,
+                      index: Value,
+                      offset: Offset32,
+                      access_size: u32,
+                      bound: HeapBound) {
+    let ty = dfg.value_type(index);
+
+    // This legalizer doesn't reason about negative heap offsets: unlike `access_size`, `offset`
+    // comes from `Offset32` and can be negative, which would otherwise flip the sign of
+    // `headroom` and turn an `as u64` cast of it into a huge value on one side of the check or
+    // the other. Since there's no valid heap access with a negative offset, just always trap.
+    let headroom = match heap_headroom(offset, access_size) {
+        Some(headroom) => headroom,
+        None => {
+            let always = dfg.ins(pos).bconst(::ir::types::B1, true);
+            emit_trap_on(dfg, pos, always, TrapCode::HeapOutOfBounds);
+            return;
+        }
+    };
+
+    let oob = match bound {
+        HeapBound::Static(bytes) => {
+            match static_limit(bytes, headroom) {
+                None => {
+                    // No index can satisfy this access; always trap.
+                    dfg.ins(pos).bconst(::ir::types::B1, true)
+                }
+                Some(limit) => {
+                    let limit = dfg.ins(pos).iconst(ty, limit as i64);
+                    dfg.ins(pos).icmp(IntCC::UnsignedGreaterThan, index, limit)
+                }
+            }
+        }
+        HeapBound::Dynamic(gv) => {
+            let bound_val = dfg.ins(pos).global_value(ty, gv);
+            let headroom_val = dfg.ins(pos).iconst(ty, headroom);
+            // `bound - headroom` underflows whenever the runtime bound can't even cover the
+            // headroom (an empty or near-empty dynamic heap, for example), wrapping to a huge
+            // limit that would wrongly let every index through. Check for that directly instead
+            // of trusting the subtraction not to wrap.
+            let bound_too_small = dfg.ins(pos)
+                .icmp(IntCC::UnsignedLessThan, bound_val, headroom_val);
+            let limit = dfg.ins(pos).isub(bound_val, headroom_val);
+            let past_limit = dfg.ins(pos).icmp(IntCC::UnsignedGreaterThan, index, limit);
+            dfg.ins(pos).bor(bound_too_small, past_limit)
+        }
+    };
+
+    emit_trap_on(dfg, pos, oob, TrapCode::HeapOutOfBounds);
+}
+
+/// Compute `offset + access_size` as the number of bytes of headroom a heap access needs beyond
+/// `index`, or `None` if `offset` is negative and this check doesn't apply.
+fn heap_headroom(offset: Offset32, access_size: u32) -> Option<i64> {
+    let offset = i64::from(offset);
+    if offset < 0 {
+        None
+    } else {
+        Some(offset + i64::from(access_size))
+    }
+}
+
+/// Compute the largest `index` that stays in bounds for a `bytes`-byte static heap, given
+/// `headroom` bytes of offset and access size beyond it, or `None` if no index can satisfy the
+/// access at all.
+fn static_limit(bytes: u64, headroom: i64) -> Option<u64> {
+    // `headroom` is never negative (see `heap_headroom`), so this cast is safe.
+    let headroom = headroom as u64;
+    if headroom > bytes {
+        None
+    } else {
+        Some(bytes - headroom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{heap_headroom, static_limit};
+    use ir::immediates::Offset32;
+
+    #[test]
+    fn negative_offset_always_traps() {
+        assert_eq!(heap_headroom(Offset32::new(-1), 4), None);
+    }
+
+    #[test]
+    fn headroom_is_offset_plus_access_size() {
+        assert_eq!(heap_headroom(Offset32::new(8), 4), Some(12));
+    }
+
+    #[test]
+    fn headroom_past_the_bound_always_traps() {
+        assert_eq!(static_limit(16, 20), None);
+    }
+
+    #[test]
+    fn limit_is_bound_minus_headroom() {
+        assert_eq!(static_limit(16, 4), Some(12));
+    }
+
+    #[test]
+    fn empty_heap_with_any_headroom_always_traps() {
+        // A zero-byte static heap can't satisfy even a single byte of access.
+        assert_eq!(static_limit(0, 1), None);
+    }
+}
@@ -0,0 +1,67 @@
+//! Legalize instructions.
+//!
+//! A legal instruction is one that can be mapped directly to a machine code instruction for the
+//! target ISA. This module provides the pieces used to change any function into an equivalent
+//! function using only legal instructions; it does not define a driver function that runs them.
+//!
+//! The characteristics of legal instructions depend on the target ISA, so any given instruction
+//! can be legal for one ISA and illegal for another.
+//!
+//! Besides transforming instructions, the legalizer also fills out the `function.signature` and
+//! `function.dfg.signatures` ABI information, and it rewrites the entry block and the arguments
+//! and results of call instructions to match the computed ABI. `return` instructions are not
+//! rewritten.
+
+use ir;
+use ir::InstBuilder;
+
+pub mod boundary;
+pub mod heap;
+pub mod table;
+mod split;
+
+pub use legalizer::split::{isplit, vsplit, split_to, concat_from, simplify_branch_arguments};
+
+/// The result of attempting to legalize a single instruction.
+///
+/// Expanding a high-level instruction like `heap_addr` or `table_addr` can itself produce
+/// instructions that aren't legal yet -- for example an `iadd` of a 64-bit index that still needs
+/// `isplit`ting on a 32-bit ISA. Returning this from an expansion function lets the legalizer
+/// driver interleave these expansions with the rest of instruction legalization instead of
+/// assuming a single pass always finishes the job.
+pub enum ExpandResult {
+    /// The instruction was already legal; nothing was done.
+    Done,
+    /// The instruction was replaced by other instructions. The driver should revisit the
+    /// program point, since the replacement instructions may themselves need legalizing.
+    Expanded,
+    /// The instruction couldn't be expanded yet because one of its operands is wider than a
+    /// register. The driver should split the operand first and retry.
+    SplitNeeded(ir::Value),
+}
+
+/// Splice a conditional trap into the current position: branch to a fresh EBB that traps with
+/// `code` when `cond` is nonzero, otherwise fall through to a fresh EBB left as the current
+/// position on return.
+///
+/// Shared by `heap` and `table` legalization so the two don't each carry their own copy of the
+/// branch/trap/continue boilerplate.
+///
+/// Note: this changes the function's EBB layout and edges without updating a `ControlFlowGraph`
+/// the caller might be holding. Callers that need to split a value flowing through the new EBBs
+/// afterwards must recompute the `ControlFlowGraph` first, since `split_any()`'s predecessor walk
+/// only knows about edges the CFG was computed from.
+pub(crate) fn emit_trap_on(dfg: &mut ir::DataFlowGraph,
+                            pos: &mut ir::Cursor,
+                            cond: ir::Value,
+                            code: ir::TrapCode) {
+    let trap_ebb = dfg.make_ebb();
+    let continue_ebb = dfg.make_ebb();
+    dfg.ins(pos).brnz(cond, trap_ebb, &[]);
+    dfg.ins(pos).jump(continue_ebb, &[]);
+
+    pos.insert_ebb(trap_ebb);
+    dfg.ins(pos).trap(code);
+
+    pos.insert_ebb(continue_ebb);
+}
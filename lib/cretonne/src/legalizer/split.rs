@@ -63,10 +63,19 @@
 //!
 //! It is possible to have circular dependencies of EBB arguments that are never used by any real
 //! instructions. These loops will remain in the program.
+//!
+//! # Splitting to a target type
+//!
+//! `isplit`/`vsplit` only halve a value once. Legalizing a value that is more than one level too
+//! wide for the ISA -- an `i128` on a 32-bit target needs four `i32` parts, not two `i64` parts --
+//! means halving it repeatedly until every part is legal. `split_to()` does this, building the
+//! split tree breadth-first so each level reuses the same concat-reuse logic as a single
+//! `isplit`/`vsplit` call, and `concat_from()` reverses the process.
 
 use flowgraph::ControlFlowGraph;
 use ir::{DataFlowGraph, Ebb, Inst, Cursor, Value, Type, Opcode, ValueDef, InstructionData,
          InstBuilder};
+use std::collections::VecDeque;
 use std::iter;
 
 /// Split `value` into two values using the `isplit` semantics. Do this by reusing existing values
@@ -253,6 +262,80 @@ fn split_value(dfg: &mut DataFlowGraph,
     }
 }
 
+/// Repeatedly split `value` until every leaf has the `target` type, reusing concats where
+/// possible at each level just like a single `isplit`/`vsplit` call would.
+///
+/// The split tree is built breadth-first: `value` is pushed onto a queue, and as long as the
+/// value at the front of the queue is wider than `target`, it is split and its two halves are
+/// pushed onto the back of the queue in low-then-high order. Processing the queue this way, front
+/// to back, naturally yields the leaves in ascending significance order once every value in the
+/// queue has reached `target`.
+///
+/// Splitting an EBB argument down more than one level schedules more than one `Repair` for the
+/// same EBB -- one per split value -- since each call to `split_any()` in the loop below runs its
+/// own (synchronous) repair of the EBB's predecessors before the next value is split. Each repair
+/// therefore always sees the predecessor branches left behind by the previous level, so the
+/// `hi_num` bookkeeping in `split_any()` stays correct even though more than two new arguments
+/// end up replacing the original one.
+pub fn split_to(dfg: &mut DataFlowGraph,
+                 cfg: &ControlFlowGraph,
+                 pos: &mut Cursor,
+                 value: Value,
+                 target: Type)
+                 -> Vec<Value> {
+    let mut worklist = VecDeque::new();
+    worklist.push_back(value);
+    let mut leaves = Vec::new();
+
+    while let Some(v) = worklist.pop_front() {
+        let ty = dfg.value_type(v);
+        if ty == target {
+            leaves.push(v);
+            continue;
+        }
+
+        let concat = if ty.is_vector() {
+            Opcode::Vconcat
+        } else {
+            Opcode::Iconcat
+        };
+        let (lo, hi) = split_any(dfg, cfg, pos, v, concat);
+        worklist.push_back(lo);
+        worklist.push_back(hi);
+    }
+
+    leaves
+}
+
+/// Reassemble `parts` -- the leaves produced by a matching call to `split_to()`, in ascending
+/// significance order -- back into a single value of `orig_type`, by inserting a balanced tree of
+/// `iconcat`/`vconcat` instructions.
+pub fn concat_from(dfg: &mut DataFlowGraph,
+                    pos: &mut Cursor,
+                    parts: &[Value],
+                    orig_type: Type)
+                    -> Value {
+    let result = concat_parts(dfg, pos, parts);
+    debug_assert_eq!(dfg.value_type(result), orig_type);
+    result
+}
+
+fn concat_parts(dfg: &mut DataFlowGraph, pos: &mut Cursor, parts: &[Value]) -> Value {
+    if parts.len() == 1 {
+        return parts[0];
+    }
+
+    let mid = parts.len() / 2;
+    let lo = concat_parts(dfg, pos, &parts[..mid]);
+    let hi = concat_parts(dfg, pos, &parts[mid..]);
+
+    if dfg.value_type(lo).is_vector() {
+        dfg.ins(pos).vconcat(lo, hi)
+    } else {
+        dfg.ins(pos).iconcat(lo, hi)
+    }
+}
+
 // Add a repair entry to the work list.
 fn add_repair(concat: Opcode,
               split_type: Type,
@@ -0,0 +1,74 @@
+//! Legalize `table_addr` instructions.
+//!
+//! Addressing into a table is bounds-checked the same way as a heap access, except the bound is
+//! expressed as a number of elements rather than bytes, and the index has to be multiplied by the
+//! element size before it can be added to the table's base address. Tables don't get the guard
+//! page treatment heaps do, since table accesses are typically rarer and the out-of-line bounds
+//! check is cheap relative to the indirect call or load that follows.
+
+use flowgraph::ControlFlowGraph;
+use ir::{Function, Inst, InstBuilder, InstructionData, TrapCode};
+use ir::condcodes::IntCC;
+use ir::entities::{Table, GlobalValue};
+use isa::TargetIsa;
+use legalizer::{ExpandResult, emit_trap_on};
+
+/// Expand a `table_addr` instruction into an explicit element-count bounds check and address
+/// computation.
+///
+/// Returns `ExpandResult::SplitNeeded` if the table index is wider than a register on `isa`, so
+/// the caller can split it with `isplit`/`vsplit` and retry.
+pub fn expand_table_addr(inst: Inst,
+                          func: &mut Function,
+                          cfg: &ControlFlowGraph,
+                          isa: &TargetIsa)
+                          -> ExpandResult {
+    let (table, index) = match func.dfg[inst] {
+        InstructionData::TableAddr { table, arg, .. } => (table, arg),
+        _ => return ExpandResult::Done,
+    };
+
+    if let Some(bad) = isa.oversize_operand(func.dfg.value_type(index)) {
+        return ExpandResult::SplitNeeded(bad);
+    }
+
+    let element_size = func.tables[table].element_size();
+    let bound_gv = table_bound(func, table);
+    let base_gv = func.tables[table].base_gv();
+
+    let mut pos = ::ir::Cursor::new(&mut func.layout);
+    pos.goto_inst(inst);
+
+    let ty = func.dfg.value_type(index);
+    let bound = func.dfg.ins(&mut pos).global_value(ty, bound_gv);
+    let oob = func.dfg
+        .ins(&mut pos)
+        .icmp(IntCC::UnsignedGreaterThanOrEqual, index, bound);
+
+    // Splitting the EBB below to hold the trap invalidates `cfg`'s view of this function; the
+    // driver must recompute it before splitting any value that flows through the new blocks.
+    let _ = cfg;
+    emit_trap_on(&mut func.dfg, &mut pos, oob, TrapCode::TableOutOfBounds);
+
+    // Do the index-to-byte-offset multiply in the pointer type rather than the index's own
+    // (possibly narrower) type, so a large but in-bounds index can't overflow the multiply and
+    // wrap to the wrong byte offset.
+    let ptr_ty = isa.pointer_type();
+    let index_ptr = if ty == ptr_ty {
+        index
+    } else {
+        func.dfg.ins(&mut pos).uextend(ptr_ty, index)
+    };
+    let base = func.dfg.ins(&mut pos).global_value(ptr_ty, base_gv);
+    let byte_offset = func.dfg
+        .ins(&mut pos)
+        .imul_imm(index_ptr, i64::from(element_size));
+    let addr = func.dfg.ins(&mut pos).iadd(base, byte_offset);
+
+    func.dfg.replace_with_aliases(inst, addr);
+    ExpandResult::Expanded
+}
+
+fn table_bound(func: &Function, table: Table) -> GlobalValue {
+    func.tables[table].bound_gv()
+}
@@ -0,0 +1,121 @@
+//! A simple GVN pass.
+//!
+//! `split_value`/`resolve_splits` only cancel out concat-then-split chains that appear locally,
+//! next to each other. If the same value is split independently in two different EBBs -- for
+//! example because both EBBs legalize an `i64` argument coming from the same dominating
+//! definition -- we still end up with two redundant `isplit` instructions computing the same
+//! pair of values.
+//!
+//! This pass is a classic available-expressions analysis, the same kind used by early C
+//! compilers to hash-cons common subexpressions: process the EBBs of a function in dominator-tree
+//! order, and for every pure instruction, compute a key from its controlling type and its full
+//! `InstructionData` -- opcode, immediates, condition codes, and operands (resolved through
+//! copies and aliases) alike. If an equivalent, dominating instruction has already been seen, the
+//! current instruction is redundant: alias its results to the earlier instruction's results and
+//! remove it. Otherwise, record it so later, dominated instructions can be matched against it.
+//!
+//! Running this before `dce()` turns duplicate `isplit`/`vsplit`/`iadd`/... results into shared
+//! values and makes the following DCE pass far more effective at cleaning up split legalization
+//! code.
+
+use dominator_tree::DominatorTree;
+use ir::{Function, DataFlowGraph, Inst, Type, InstructionData};
+use std::collections::HashMap;
+
+/// A key identifying the value computed by a pure instruction, independent of where it appears in
+/// the program.
+///
+/// This has to be the *whole* `InstructionData`, not just the opcode and operands: two
+/// `iconst`s of the same type but different immediates, or two `icmp`s with different condition
+/// codes, have no `Value` operands to tell them apart, so keying only on `(opcode, ctrl_type,
+/// args)` would hash-cons them together and silently merge distinct constants or comparisons.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct GvnKey {
+    ctrl_type: Type,
+    data: InstructionData,
+}
+
+/// Run the simple GVN pass on `func`, using `domtree` to determine which earlier instructions
+/// dominate a candidate for replacement.
+pub fn simple_gvn(func: &mut Function, domtree: &DominatorTree) {
+    let mut table: HashMap<GvnKey, Inst> = HashMap::new();
+
+    for ebb in domtree.cfg_postorder().iter().rev() {
+        let insts: Vec<Inst> = func.layout.ebb_insts(*ebb).collect();
+        for inst in insts {
+            if !is_pure(&func.dfg, inst) {
+                continue;
+            }
+
+            let key = match make_key(&mut func.dfg, inst) {
+                Some(key) => key,
+                None => continue,
+            };
+
+            match table.get(&key).cloned() {
+                Some(earlier) if domtree.dominates(earlier, inst, &func.layout) => {
+                    // `inst` computes the same value as `earlier`, and `earlier` dominates it, so
+                    // every result of `inst` can be redirected to the matching result of
+                    // `earlier`.
+                    let num_results = func.dfg.inst_results(inst).len();
+                    for i in 0..num_results {
+                        let old = func.dfg.inst_results(inst)[i];
+                        let new = func.dfg.inst_results(earlier)[i];
+                        func.dfg.change_to_alias(old, new);
+                    }
+                    func.layout.remove_inst(inst);
+                }
+                Some(_) => {
+                    // The table holds an equivalent instruction, but it doesn't dominate `inst`,
+                    // so it can't be reused here. Leave it in place rather than overwriting it
+                    // with `inst`, which doesn't dominate it either -- a later sibling in the
+                    // dominator-tree walk could still match the existing entry, and replacing it
+                    // would lose that candidate for no benefit.
+                }
+                None => {
+                    table.insert(key, inst);
+                }
+            }
+        }
+    }
+}
+
+/// Can `inst` be safely hash-consed? Pure instructions only: no side effects, no control flow.
+fn is_pure(dfg: &DataFlowGraph, inst: Inst) -> bool {
+    let opcode = dfg[inst].opcode();
+    !opcode.is_branch() && !opcode.is_call() && !opcode.is_return() && !opcode.can_trap() &&
+    !opcode.can_store() && !opcode.can_load() && !opcode.other_side_effects()
+}
+
+/// Build the `GvnKey` for `inst`.
+///
+/// This canonicalizes `inst`'s operands in place -- resolving them through copies and aliases,
+/// and for commutative opcodes sorting the (now-resolved) pair -- so that `a op b` and `b op a`
+/// end up with identical `InstructionData`, before cloning it into the key. Every other field of
+/// `InstructionData` (immediates, condition codes, the opcode itself) rides along unchanged, so
+/// instructions that only differ in those fields no longer collide.
+fn make_key(dfg: &mut DataFlowGraph, inst: Inst) -> Option<GvnKey> {
+    // Instructions with multiple results (like `isplit`) are still eligible: the key identifies
+    // the whole instruction, and every result is redirected together when a match is found.
+    if let InstructionData::VariableArgs { .. } = dfg[inst] {
+        // Variable-argument instructions (branches, calls) are never pure, so this shouldn't come
+        // up, but skip them defensively rather than risk hashing an incomplete key.
+        return None;
+    }
+
+    let opcode = dfg[inst].opcode();
+    let mut resolved: Vec<_> = dfg.inst_args(inst)
+        .iter()
+        .map(|&v| dfg.resolve_copies(v))
+        .collect();
+    if opcode.is_commutative() && resolved.len() == 2 && resolved[1] < resolved[0] {
+        resolved.swap(0, 1);
+    }
+    dfg.inst_args_mut(inst).copy_from_slice(&resolved);
+
+    let ctrl_type = dfg.ctrl_typevar(inst);
+    Some(GvnKey {
+             ctrl_type: ctrl_type,
+             data: dfg[inst].clone(),
+         })
+}